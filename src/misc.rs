@@ -2,6 +2,9 @@
 ///! Misc stuff used throughout the crate.
 ///!
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::collections::TryReserveError;
 use std::ptr;
 
 ///
@@ -17,6 +20,171 @@ pub struct NamedStorage<T: ?Sized> {
     pub contents: Box<T>,
 }
 
+impl<T: Persistable + ?Sized> NamedStorage<T> {
+    /// Flush the serialized `contents` of this storage to `backend`,
+    /// under its `name` key. Telemetry is purely in-memory otherwise, so
+    /// this is the only thing that survives a crash or restart.
+    pub fn flush(&self, backend: &dyn StorageBackend) -> Result<(), StorageError> {
+        backend.put(&self.name, &self.contents.to_bytes())
+    }
+}
+
+impl<T: Persistable> NamedStorage<T> {
+    /// Reload `contents` from whatever was last flushed to `backend`
+    /// under this storage's `name` key, typically called on startup.
+    pub fn reload(&mut self, backend: &dyn StorageBackend) -> Result<(), StorageError> {
+        let bytes = backend.get(&self.name)?;
+        self.contents = Box::new(T::from_bytes(&bytes)?);
+        Ok(())
+    }
+}
+
+///
+/// Implemented by storage contents (typically a `PlainRawStorage` or
+/// `KeyedRawStorage`) that can be flushed to and reloaded from a
+/// `StorageBackend`.
+///
+pub trait Persistable {
+    /// Serialize `self` for storage under a `StorageBackend`.
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Deserialize a value previously produced by `to_bytes`.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, StorageError> where Self: Sized;
+}
+
+///
+/// Error produced by a `StorageBackend`.
+///
+#[derive(Debug)]
+pub enum StorageError {
+    /// No value is stored under the requested name.
+    NotFound,
+
+    /// The stored bytes could not be turned into (or from) a value.
+    SerializationError,
+
+    /// The backend itself failed, e.g. an I/O error on an on-disk backend.
+    BackendError,
+}
+
+///
+/// A place `NamedStorage` can durably persist its serialized `contents`,
+/// so telemetry survives a crash or restart.
+///
+/// The crate ships a default in-memory implementation; embedders that
+/// want real durability supply their own, e.g. backed by a file.
+///
+pub trait StorageBackend {
+    /// Fetch the bytes last `put` under `name`, or `StorageError::NotFound`
+    /// if there are none.
+    fn get(&self, name: &str) -> Result<Vec<u8>, StorageError>;
+
+    /// Store `bytes` under `name`, replacing whatever was there before.
+    fn put(&self, name: &str, bytes: &[u8]) -> Result<(), StorageError>;
+
+    /// Remove whatever is stored under `name`, if anything.
+    fn del(&self, name: &str) -> Result<(), StorageError>;
+}
+
+///
+/// Default, in-memory `StorageBackend`. Provides no durability across
+/// process restarts, but needs no setup and is always available.
+///
+#[derive(Default)]
+pub struct MemoryStorageBackend {
+    entries: RefCell<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorageBackend {
+    pub fn new() -> MemoryStorageBackend {
+        MemoryStorageBackend::default()
+    }
+}
+
+impl StorageBackend for MemoryStorageBackend {
+    fn get(&self, name: &str) -> Result<Vec<u8>, StorageError> {
+        match self.entries.borrow().get(name) {
+            Some(bytes) => Ok(bytes.clone()),
+            None => Err(StorageError::NotFound),
+        }
+    }
+
+    fn put(&self, name: &str, bytes: &[u8]) -> Result<(), StorageError> {
+        self.entries.borrow_mut().insert(name.to_string(), bytes.to_vec());
+        Ok(())
+    }
+
+    fn del(&self, name: &str) -> Result<(), StorageError> {
+        self.entries.borrow_mut().remove(name);
+        Ok(())
+    }
+}
+
+///
+/// Storage that can reclaim memory after a reporting cycle: shrinking a
+/// dense bucket array back down to fit its contents, or dropping keyed
+/// entries that are only holding onto capacity for a count that has gone
+/// back to zero.
+///
+pub trait Compactable {
+    /// Shrink this storage's capacity down to fit its current contents.
+    fn compact(&mut self);
+
+    /// Shrink this storage's capacity down towards `min_capacity`,
+    /// without necessarily fitting its current contents exactly.
+    ///
+    /// Named `shrink_capacity_to` rather than `shrink_to` because the
+    /// inherent `Vec`/`HashMap` methods of that name take priority over
+    /// trait methods, so a call on a `Vec` or `HashMap` would not reach it.
+    fn shrink_capacity_to(&mut self, min_capacity: usize);
+}
+
+impl<T> Compactable for Vec<T> {
+    fn compact(&mut self) {
+        self.shrink_to_fit();
+    }
+
+    fn shrink_capacity_to(&mut self, min_capacity: usize) {
+        self.shrink_to(min_capacity);
+    }
+}
+
+impl Compactable for HashMap<usize, u32> {
+    fn compact(&mut self) {
+        self.retain(|_, count| *count != 0);
+        self.shrink_to_fit();
+    }
+
+    fn shrink_capacity_to(&mut self, min_capacity: usize) {
+        self.shrink_to(min_capacity);
+    }
+}
+
+impl<T: Compactable + ?Sized> NamedStorage<T> {
+    /// Reclaim memory held by this storage's `contents` after a
+    /// reporting cycle, instead of holding onto peak capacity forever.
+    pub fn compact(&mut self) {
+        self.contents.compact();
+    }
+
+    /// Shrink the capacity held by this storage's `contents` towards
+    /// `min_capacity`, without necessarily fitting its current contents
+    /// exactly.
+    pub fn shrink_capacity_to(&mut self, min_capacity: usize) {
+        self.contents.shrink_capacity_to(min_capacity);
+    }
+}
+
+/// Compact every storage in `storages` at once, e.g. right after a
+/// reporting cycle has flushed them all through a `StorageBackend`.
+pub fn compact_all<'a, I>(storages: I)
+    where I: IntoIterator<Item = &'a mut NamedStorage<dyn Compactable>>
+{
+    for storage in storages {
+        storage.compact();
+    }
+}
+
 ///
 /// A subset of data to serialize.
 ///
@@ -42,6 +210,18 @@ pub enum SerializationFormat {
     /// - ...
     ///
     SimpleJson,
+
+    ///
+    /// Sparse Json, for histograms that are mostly empty:
+    /// - `Linear` and friends are represented as an object mapping the
+    ///    *minimum value* of each non-zero bucket (not its index) to its
+    ///    count, e.g. `{"0": 3, "16": 1, "256": 5}`, skipping zero buckets
+    ///    entirely;
+    /// - `KeyedLinear` are represented as for `KeyedLinear` in `SimpleJson`,
+    ///    but with each histogram serialized as above;
+    /// - ...
+    ///
+    SparseJson,
 }
 
 ///
@@ -106,19 +286,161 @@ impl LinearBuckets {
             res as usize
         }
     }
+
+    /// The minimum value that falls into bucket `index`, used by
+    /// `SerializationFormat::SparseJson` to key its output by bucket
+    /// minimum rather than bucket index.
+    ///
+    /// Computed in `f64` to keep rounding error well below that of
+    /// `get_bucket`'s own `f32` arithmetic; for `min`/`max` far enough
+    /// apart that `f32` can no longer represent every value exactly,
+    /// `get_bucket(bucket_minimum(index))` may still land one bucket off.
+    pub fn bucket_minimum(&self, index: usize) -> u32 {
+        if index == 0 {
+            0
+        } else {
+            let den = self.max as f64 - self.min as f64;
+            // `get_bucket` floors `(value - min) / den * buckets`, so the
+            // smallest `value` that maps back to `index` is the ceiling
+            // of its inverse, not the floor.
+            self.min + ((index as f64 * den) / self.buckets as f64).ceil() as u32
+        }
+    }
 }
 
+//
+// Representation of buckets shared by both plain and keyed exponential
+// histograms.
+//
+// Unlike `LinearBuckets`, the boundaries are not evenly spaced but grow
+// geometrically, which wastes fewer cells on the long-tail distributions
+// common in timing/memory telemetry.
+//
+pub struct ExponentialBuckets {
+    max: u32, // Invariant: max > min
+    pub buckets: usize,
+
+    // Precomputed bucket minimums. `minimums[0]` is always `0`, reserved
+    // for values below `min`.
+    minimums: Vec<u32>,
+}
+
+impl ExponentialBuckets {
+    pub fn new(min: u32, max: u32, buckets: usize) -> ExponentialBuckets {
+        assert!(min < max);
+        // Need at least one underflow bucket (0), one interior boundary
+        // and one overflow bucket, or `(i-1)/(buckets-2)` below is `0/0`.
+        assert!(buckets >= 3);
+        assert!(min >= 1);
+
+        let mut minimums = Vec::with_capacity(buckets);
+        minimums.push(0);
+
+        let log_min = (min as f64).ln();
+        let log_max = (max as f64).ln();
+        for i in 1..buckets {
+            let ratio = (i - 1) as f64 / (buckets - 2) as f64;
+            let boundary = (log_min + (log_max - log_min) * ratio).exp().round() as u32;
+            let previous = minimums[i - 1];
+            if boundary <= previous {
+                minimums.push(previous + 1);
+            } else {
+                minimums.push(boundary);
+            }
+        }
+
+        ExponentialBuckets {
+            max: max,
+            buckets: buckets,
+            minimums: minimums,
+        }
+    }
+
+    pub fn get_bucket(&self, value: u32) -> usize {
+        if value >= self.max {
+            return self.buckets - 1;
+        }
+        // Binary search for the largest boundary <= value.
+        match self.minimums.binary_search(&value) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        }
+    }
+
+    /// The minimum value that falls into bucket `index`, used by
+    /// `SerializationFormat::SparseJson` to key its output by bucket
+    /// minimum rather than bucket index.
+    pub fn bucket_minimum(&self, index: usize) -> u32 {
+        self.minimums[index]
+    }
+}
+
+//
+// Representation of buckets shared by both plain and keyed functional
+// histograms.
+//
+// Unlike `LinearBuckets` and `ExponentialBuckets`, there is no fixed `max`
+// and no precomputed array of boundaries: bucket indices are computed by a
+// closed-form function, so the backing storage for these histograms is a
+// sparse `HashMap<usize, u32>` rather than a dense `Vec`.
+//
+pub struct FunctionalBuckets {
+    exponent: f64, // Precomputed: log_base.powf(1.0 / buckets_per_magnitude)
+}
+
+impl FunctionalBuckets {
+    pub fn new(log_base: f64, buckets_per_magnitude: f64) -> FunctionalBuckets {
+        assert!(log_base > 1.0);
+        assert!(buckets_per_magnitude > 0.0);
+
+        let exponent = log_base.powf(1.0 / buckets_per_magnitude);
+        FunctionalBuckets {
+            exponent: exponent,
+        }
+    }
+
+    pub fn sample_to_bucket_index(&self, sample: u32) -> usize {
+        if sample == 0 {
+            return 0;
+        }
+        ((sample as f64 + 1.0).ln() / self.exponent.ln()).floor() as usize
+    }
+
+    pub fn bucket_index_to_minimum(&self, index: usize) -> u32 {
+        self.exponent.powf(index as f64).floor() as u32
+    }
+
+    /// Alias for `bucket_index_to_minimum`, used by
+    /// `SerializationFormat::SparseJson` to key its output by bucket
+    /// minimum rather than bucket index, as for the other bucketing types.
+    pub fn bucket_minimum(&self, index: usize) -> u32 {
+        self.bucket_index_to_minimum(index)
+    }
+}
+
+//
+// Sparse storage for histograms whose bucket count is not known ahead of
+// time, such as `FunctionalBuckets`. Entries are created on demand, so
+// `contents` can grow without bound as new bucket indices are touched.
+//
+pub type SparseStorage = HashMap<usize, u32>;
+
 /// Partial reimplementation of `Vec::resize`, until this method has
 /// reached the stable version of Rust.
-pub fn vec_resize<T>(vec: &mut Vec<T>, min_len: usize, value: T)
+///
+/// Fallible: unlike `Vec::reserve`, this never aborts the process on
+/// allocation failure, instead reporting it through `TryReserveError`.
+/// Callers that register very large keyed histograms can therefore
+/// recover instead of taking an OOM abort.
+pub fn try_vec_resize<T>(vec: &mut Vec<T>, min_len: usize, value: T) -> Result<(), TryReserveError>
     where T: Clone
 {
     let len = vec.len();
     if min_len <= len {
-        return;
+        return Ok(());
     }
     let delta = min_len - len;
-    vec.reserve(delta);
+    vec.try_reserve(delta)?;
     unsafe {
         let mut ptr = vec.as_mut_ptr().offset(len as isize);
         // Write all elements except the last one
@@ -133,19 +455,156 @@ pub fn vec_resize<T>(vec: &mut Vec<T>, min_len: usize, value: T)
         ptr::write(ptr, value);
         vec.set_len(len + delta);
     }
+    Ok(())
+}
+
+/// Thin wrapper over `try_vec_resize` kept for source compatibility with
+/// callers that have not been updated to handle allocation failure.
+pub fn vec_resize<T>(vec: &mut Vec<T>, min_len: usize, value: T)
+    where T: Clone
+{
+    try_vec_resize(vec, min_len, value).unwrap()
 }
 
+/// Fallible: see `try_vec_resize` for why this does not simply abort on
+/// allocation failure.
+pub fn try_vec_with_size<T>(size: usize, value: T) -> Result<Vec<T>, TryReserveError>
+    where T: Clone
+{
+    let mut vec = Vec::new();
+    try_vec_resize(&mut vec, size, value)?;
+    Ok(vec)
+}
+
+/// Thin wrapper over `try_vec_with_size` kept for source compatibility
+/// with callers that have not been updated to handle allocation failure.
 pub fn vec_with_size<T>(size: usize, value: T) -> Vec<T>
     where T: Clone
 {
-    let mut vec = Vec::with_capacity(size);
-    unsafe {
-        // Resize. In future versions of Rust, we should
-        // be able to use `vec.resize`.
-        vec.set_len(size);
-        for i in 0 .. size {
-            vec[i] = value.clone();
+    try_vec_with_size(size, value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_buckets_get_bucket_round_trips_minimum() {
+        let buckets = LinearBuckets::new(1, 1000, 10);
+        for i in 0..buckets.buckets {
+            let minimum = buckets.bucket_minimum(i);
+            assert_eq!(buckets.get_bucket(minimum), i);
+        }
+    }
+
+    #[test]
+    fn exponential_buckets_below_min_and_above_max() {
+        let buckets = ExponentialBuckets::new(1, 1000, 10);
+        assert_eq!(buckets.get_bucket(0), 0);
+        assert_eq!(buckets.get_bucket(1000), buckets.buckets - 1);
+        assert_eq!(buckets.get_bucket(1_000_000), buckets.buckets - 1);
+    }
+
+    #[test]
+    fn exponential_buckets_minimums_are_monotonic() {
+        let buckets = ExponentialBuckets::new(1, 1000, 10);
+        for i in 1..buckets.buckets {
+            assert!(buckets.bucket_minimum(i) > buckets.bucket_minimum(i - 1));
+        }
+    }
+
+    #[test]
+    fn exponential_buckets_get_bucket_round_trips_minimum() {
+        let buckets = ExponentialBuckets::new(1, 1000, 10);
+        for i in 0..buckets.buckets {
+            let minimum = buckets.bucket_minimum(i);
+            assert_eq!(buckets.get_bucket(minimum), i);
+        }
+    }
+
+    #[test]
+    fn functional_buckets_zero_sample_maps_to_bucket_zero() {
+        let buckets = FunctionalBuckets::new(2.0, 8.0);
+        assert_eq!(buckets.sample_to_bucket_index(0), 0);
+        assert_eq!(buckets.bucket_index_to_minimum(0), 1);
+    }
+
+    #[test]
+    fn functional_buckets_index_to_minimum_is_monotonic() {
+        let buckets = FunctionalBuckets::new(2.0, 8.0);
+        let mut previous = buckets.bucket_index_to_minimum(0);
+        for i in 1..50 {
+            let minimum = buckets.bucket_index_to_minimum(i);
+            assert!(minimum >= previous);
+            previous = minimum;
         }
     }
-    vec
+
+    #[test]
+    fn functional_buckets_sample_to_bucket_index_is_monotonic() {
+        let buckets = FunctionalBuckets::new(2.0, 8.0);
+        let mut previous = buckets.sample_to_bucket_index(0);
+        for sample in 1..10_000 {
+            let index = buckets.sample_to_bucket_index(sample);
+            assert!(index >= previous);
+            previous = index;
+        }
+    }
+
+    #[test]
+    fn functional_buckets_does_not_panic_at_u32_max() {
+        let buckets = FunctionalBuckets::new(2.0, 8.0);
+        buckets.sample_to_bucket_index(u32::max_value());
+    }
+
+    #[test]
+    fn memory_storage_backend_put_get_del_round_trip() {
+        let backend = MemoryStorageBackend::new();
+        backend.put("name", &[1, 2, 3]).unwrap();
+        assert_eq!(backend.get("name").unwrap(), vec![1, 2, 3]);
+
+        backend.del("name").unwrap();
+        match backend.get("name") {
+            Err(StorageError::NotFound) => (),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn memory_storage_backend_get_missing_is_not_found() {
+        let backend = MemoryStorageBackend::new();
+        match backend.get("absent") {
+            Err(StorageError::NotFound) => (),
+            other => panic!("expected NotFound, got {:?}", other),
+        }
+    }
+
+    impl Persistable for Vec<u32> {
+        fn to_bytes(&self) -> Vec<u8> {
+            self.iter().flat_map(|cell| cell.to_le_bytes()).collect()
+        }
+
+        fn from_bytes(bytes: &[u8]) -> Result<Self, StorageError> {
+            if bytes.len() % 4 != 0 {
+                return Err(StorageError::SerializationError);
+            }
+            Ok(bytes.chunks(4)
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn named_storage_flush_and_reload_round_trip() {
+        let backend = MemoryStorageBackend::new();
+        let mut storage = NamedStorage {
+            name: "histogram".to_string(),
+            contents: Box::new(vec![1u32, 2, 3]),
+        };
+        storage.flush(&backend).unwrap();
+
+        storage.contents = Box::new(Vec::new());
+        storage.reload(&backend).unwrap();
+        assert_eq!(*storage.contents, vec![1, 2, 3]);
+    }
 }